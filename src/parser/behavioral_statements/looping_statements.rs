@@ -1,13 +1,148 @@
 use crate::parser::*;
 use nom::branch::*;
 use nom::combinator::*;
+use nom::error::{context, ContextError, ErrorKind, ParseError as NomParseError};
 use nom::multi::*;
 use nom::sequence::*;
 use nom::IResult;
+use nom::Slice;
+use nom_locate::LocatedSpan;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// -----------------------------------------------------------------------------
+
+/// Parser input: a `&str` fragment carrying its offset from the start of the
+/// original source, plus the line/column of that offset, so every grammar
+/// production below can recover where it came from.
+pub type LocatedStr<'a> = LocatedSpan<&'a str>;
+
+/// The resolved location of an AST node in the original source, captured by
+/// snapshotting a [`LocatedStr`] before the first token of a production and
+/// after its last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: u32,
+    pub col: usize,
+}
+
+impl Span {
+    fn new(start: LocatedStr, end: LocatedStr) -> Self {
+        Span {
+            offset: start.location_offset(),
+            len: end.location_offset().saturating_sub(start.location_offset()),
+            line: start.location_line(),
+            col: start.get_utf8_column(),
+        }
+    }
+
+    fn point(at: LocatedStr) -> Self {
+        Span::new(at, at)
+    }
+}
+
+/// The error type threaded through the loop-statement parsers. Once a loop
+/// keyword has disambiguated which production we're in (see [`cut`] usage
+/// below), a failure inside that production is reported here instead of
+/// being swallowed by `alt`'s backtracking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ParseError {
+    pub expected: String,
+    pub at: Span,
+}
+
+impl ParseError {
+    /// Render a single-caret diagnostic against `source`, e.g.:
+    ///
+    /// ```text
+    /// error: expected `;` after the for-initialization
+    ///   --> line 3, column 12
+    ///     for (i = 0 j < 10; i++)
+    ///                ^
+    /// ```
+    pub fn report(&self, source: &str) -> String {
+        let line_text = source.lines().nth(self.at.line.saturating_sub(1) as usize);
+        let caret = " ".repeat(self.at.col.saturating_sub(1));
+        format!(
+            "error: expected {}\n  --> line {}, column {}\n    {}\n    {}^",
+            self.expected,
+            self.at.line,
+            self.at.col,
+            line_text.unwrap_or(""),
+            caret
+        )
+    }
+}
+
+impl<'a> NomParseError<LocatedStr<'a>> for ParseError {
+    fn from_error_kind(input: LocatedStr<'a>, _kind: ErrorKind) -> Self {
+        ParseError {
+            expected: "valid syntax".to_string(),
+            at: Span::point(input),
+        }
+    }
+
+    fn append(_input: LocatedStr<'a>, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a> ContextError<LocatedStr<'a>> for ParseError {
+    fn add_context(_input: LocatedStr<'a>, ctx: &'static str, other: Self) -> Self {
+        ParseError {
+            expected: ctx.to_string(),
+            ..other
+        }
+    }
+}
+
+/// A single error recovered from while parsing, as collected by
+/// [`loop_statement_recovering`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub error: ParseError,
+    pub skipped: Span,
+}
+
+/// Skip input until a synchronizing token (`;`, `end`, or an unmatched
+/// `)`/`]`) is found, so one malformed loop doesn't abort the rest of the
+/// parse. `from_offset` is the absolute offset the failure was reported at.
+fn resync(s: LocatedStr, from_offset: usize) -> LocatedStr {
+    let bytes = s.fragment().as_bytes();
+    let mut i = from_offset.saturating_sub(s.location_offset()).min(bytes.len());
+    let mut depth: i32 = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'(' | b'[' => depth += 1,
+            b')' | b']' if depth > 0 => depth -= 1,
+            b')' | b']' => return s.slice(i..),
+            b';' if depth == 0 => return s.slice(i + 1..),
+            b'e' if depth == 0 && bytes[i..].starts_with(b"end") => {
+                let before_ok = i == 0 || !is_ident_byte(bytes[i - 1]);
+                let after_ok = bytes.get(i + 3).map_or(true, |&b| !is_ident_byte(b));
+                if before_ok && after_ok {
+                    return s.slice(i..);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    s.slice(bytes.len()..)
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
 
 // -----------------------------------------------------------------------------
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LoopStatement<'a> {
     Forever(LoopStatementForever<'a>),
     Repeat(LoopStatementRepeat<'a>),
@@ -15,66 +150,137 @@ pub enum LoopStatement<'a> {
     For(LoopStatementFor<'a>),
     DoWhile(LoopStatementDoWhile<'a>),
     Foreach(LoopStatementForeach<'a>),
+    /// A loop statement that failed to parse; recovered by
+    /// [`loop_statement_recovering`] by skipping to a synchronizing token.
+    Error(LoopStatementError),
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LoopStatementError {
+    pub skipped: Span,
+    pub error: ParseError,
+}
+
+/// A loop body statement, or a local recovery placeholder if it failed to
+/// parse. `StatementOrNull` lives outside this module and can't gain an
+/// `Error` variant of its own, so loop bodies carry one at this level
+/// instead -- giving a single bad statement inside a loop body the same
+/// recovery granularity `LoopStatement::Error` gives a whole malformed
+/// loop header, rather than discarding the entire loop (header, span, and
+/// all) over one bad statement in its body.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LoopBody<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Statement(StatementOrNull<'a>),
+    Error(LoopStatementError),
 }
 
+/// Same recovery granularity as [`LoopBody`], but for `foreach`, whose body
+/// is a `Statement` rather than a `StatementOrNull`.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ForeachBody<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Statement(Statement<'a>),
+    Error(LoopStatementError),
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementForever<'a> {
-    pub nodes: (StatementOrNull<'a>,),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub nodes: (LoopBody<'a>,),
+    pub span: Span,
+    /// Span of the `forever` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementRepeat<'a> {
-    pub nodes: (Expression<'a>, StatementOrNull<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub nodes: (Expression<'a>, LoopBody<'a>),
+    pub span: Span,
+    /// Span of the `repeat` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementWhile<'a> {
-    pub nodes: (Expression<'a>, StatementOrNull<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub nodes: (Expression<'a>, LoopBody<'a>),
+    pub span: Span,
+    /// Span of the `while` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementFor<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub nodes: (
         Option<ForInitialization<'a>>,
         Option<Expression<'a>>,
         Option<Vec<ForStepAssignment<'a>>>,
-        StatementOrNull<'a>,
+        LoopBody<'a>,
     ),
+    pub span: Span,
+    /// Span of the `for` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementDoWhile<'a> {
-    pub nodes: (StatementOrNull<'a>, Expression<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub nodes: (LoopBody<'a>, Expression<'a>),
+    pub span: Span,
+    /// Span of the `do` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopStatementForeach<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub nodes: (
         PsOrHierarchicalArrayIdentifier<'a>,
         LoopVariables<'a>,
-        Statement<'a>,
+        ForeachBody<'a>,
     ),
+    pub span: Span,
+    /// Span of the `foreach` keyword itself.
+    pub keyword: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ForInitialization<'a> {
     Assignment(Vec<VariableAssignment<'a>>),
     Declaration(Vec<ForVariableDeclaration<'a>>),
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ForVariableDeclaration<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub nodes: (
         Option<Var>,
         DataType<'a>,
         Vec<(VariableIdentifier<'a>, Expression<'a>)>,
     ),
+    pub span: Span,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Var {}
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ForStepAssignment<'a> {
     Operator(OperatorAssignment<'a>),
     IncOrDec(IncOrDecExpression<'a>),
@@ -82,13 +288,100 @@ pub enum ForStepAssignment<'a> {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LoopVariables<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub nodes: (Vec<Option<IndexVariableIdentifier<'a>>>,),
+    pub span: Span,
 }
 
 // -----------------------------------------------------------------------------
 
-pub fn loop_statement(s: &str) -> IResult<&str, LoopStatement> {
+/// Adapts a crate-wide parser that's still concretely typed over `&str`
+/// (`symbol`, `expression`, `statement_or_null`, ...) to this module's
+/// `LocatedStr`/`ParseError` types. It runs `f` against the located input's
+/// underlying fragment and re-derives the consumed span from how much of
+/// that fragment got eaten, so the rest of the crate doesn't need to move
+/// onto a located input before this module can track spans of its own.
+/// On failure the offending offset is recovered the same way, from how much
+/// of the fragment nom's error says is left.
+fn located<'a, O>(
+    mut f: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(LocatedStr<'a>) -> IResult<LocatedStr<'a>, O, ParseError> {
+    move |s: LocatedStr<'a>| {
+        let input = *s.fragment();
+        f(input)
+            .map(|(rest, x)| (s.slice((input.len() - rest.len())..), x))
+            .map_err(|e| {
+                e.map(|inner| ParseError {
+                    expected: "valid syntax".to_string(),
+                    at: Span::point(s.slice((input.len() - inner.input.len())..)),
+                })
+            })
+    }
+}
+
+/// Wraps the shared `symbol` combinator to also capture the `Span` of the
+/// matched keyword/punctuation itself, so a loop statement's own `for`/
+/// `while`/etc. token is locatable and not just the production as a whole.
+/// `symbol` lives outside this module and isn't touched here -- this is a
+/// local wrapper, used only where this module wants a keyword's span.
+///
+/// `symbol` skips leading whitespace/comments before matching its tag, so
+/// this can't just snapshot the position before calling it -- that would
+/// include the skipped whitespace in the span. Instead it uses how much of
+/// the fragment the matched tag text itself accounts for to locate exactly
+/// where the token starts.
+fn located_symbol(tag: &'static str) -> impl FnMut(LocatedStr) -> IResult<LocatedStr, Span, ParseError> {
+    move |s: LocatedStr| {
+        let input = *s.fragment();
+        symbol(tag)(input)
+            .map(|(rest, matched)| {
+                let consumed = input.len() - rest.len();
+                let token_start = s.slice((consumed - matched.len())..);
+                let end = s.slice(consumed..);
+                (end, Span::new(token_start, end))
+            })
+            .map_err(|e| {
+                e.map(|inner| ParseError {
+                    expected: format!("`{}`", tag),
+                    at: Span::point(s.slice((input.len() - inner.input.len())..)),
+                })
+            })
+    }
+}
+
+/// Parses a loop body statement, recovering locally if it's malformed rather
+/// than failing the whole enclosing loop. A committed failure here means a
+/// statement was attempted and broke, not that the body is missing, so it's
+/// skipped-and-recorded the same way [`loop_statement_recovering`] recovers a
+/// whole bad loop, just at body-statement granularity.
+fn loop_body(s: LocatedStr) -> IResult<LocatedStr, LoopBody, ParseError> {
+    match context("a statement for the loop body", cut(located(statement_or_null)))(s) {
+        Ok((rest, x)) => Ok((rest, LoopBody::Statement(x))),
+        Err(nom::Err::Failure(error)) => {
+            let rest = resync(s, error.at.offset);
+            let skipped = Span::new(s, rest);
+            Ok((rest, LoopBody::Error(LoopStatementError { skipped, error })))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// [`loop_body`]'s counterpart for `foreach`, whose body is a `Statement`.
+fn foreach_body(s: LocatedStr) -> IResult<LocatedStr, ForeachBody, ParseError> {
+    match context("a statement for the loop body", cut(located(statement)))(s) {
+        Ok((rest, x)) => Ok((rest, ForeachBody::Statement(x))),
+        Err(nom::Err::Failure(error)) => {
+            let rest = resync(s, error.at.offset);
+            let skipped = Span::new(s, rest);
+            Ok((rest, ForeachBody::Error(LoopStatementError { skipped, error })))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn loop_statement(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
     alt((
         loop_statement_forever,
         loop_statement_repeat,
@@ -99,130 +392,682 @@ pub fn loop_statement(s: &str) -> IResult<&str, LoopStatement> {
     ))(s)
 }
 
-pub fn loop_statement_forever(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("forever")(s)?;
-    let (s, x) = statement_or_null(s)?;
+pub fn loop_statement_forever(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("forever")(s)?;
+    let (s, x) = loop_body(s)?;
     Ok((
         s,
-        LoopStatement::Forever(LoopStatementForever { nodes: (x,) }),
+        LoopStatement::Forever(LoopStatementForever {
+            nodes: (x,),
+            span: Span::new(start, s),
+            keyword,
+        }),
     ))
 }
 
-pub fn loop_statement_repeat(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("repeat")(s)?;
-    let (s, _) = symbol("(")(s)?;
-    let (s, x) = expression(s)?;
-    let (s, _) = symbol(")")(s)?;
-    let (s, y) = statement_or_null(s)?;
+pub fn loop_statement_repeat(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("repeat")(s)?;
+    let (s, _) = located(symbol("("))(s)?;
+    let (s, x) = context("expected a repeat count expression", cut(located(expression)))(s)?;
+    let (s, _) = context("`)` closing the repeat count", cut(located(symbol(")"))))(s)?;
+    let (s, y) = loop_body(s)?;
     Ok((
         s,
-        LoopStatement::Repeat(LoopStatementRepeat { nodes: (x, y) }),
+        LoopStatement::Repeat(LoopStatementRepeat {
+            nodes: (x, y),
+            span: Span::new(start, s),
+            keyword,
+        }),
     ))
 }
 
-pub fn loop_statement_while(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("while")(s)?;
-    let (s, _) = symbol("(")(s)?;
-    let (s, x) = expression(s)?;
-    let (s, _) = symbol(")")(s)?;
-    let (s, y) = statement_or_null(s)?;
+pub fn loop_statement_while(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("while")(s)?;
+    let (s, _) = located(symbol("("))(s)?;
+    let (s, x) = context("expected a while condition expression", cut(located(expression)))(s)?;
+    let (s, _) = context("`)` closing the while condition", cut(located(symbol(")"))))(s)?;
+    let (s, y) = loop_body(s)?;
     Ok((
         s,
-        LoopStatement::While(LoopStatementWhile { nodes: (x, y) }),
+        LoopStatement::While(LoopStatementWhile {
+            nodes: (x, y),
+            span: Span::new(start, s),
+            keyword,
+        }),
     ))
 }
 
-pub fn loop_statement_for(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("for")(s)?;
-    let (s, _) = symbol("(")(s)?;
-    let (s, x) = opt(for_initialization)(s)?;
-    let (s, _) = symbol(";")(s)?;
-    let (s, y) = opt(expression)(s)?;
-    let (s, _) = symbol(";")(s)?;
-    let (s, z) = opt(for_step)(s)?;
-    let (s, _) = symbol(")")(s)?;
-    let (s, v) = statement_or_null(s)?;
+pub fn loop_statement_for(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("for")(s)?;
+    let (s, _) = located(symbol("("))(s)?;
+    // `opt(cut(...))` (or `cut(opt(...))`) can't tell "genuinely absent" from
+    // "present but malformed": `opt` swallows the inner `Err::Error` into
+    // `Ok(None)` before `cut` ever sees it. Instead, peek for the clause's
+    // terminator first -- only when it's NOT next do we commit to parsing
+    // (and hard-failing on) the clause.
+    let (s, x) = alt((
+        map(peek(located(symbol(";"))), |_| None),
+        map(cut(for_initialization), Some),
+    ))(s)?;
+    let (s, _) = context("`;` after the for-initialization", cut(located(symbol(";"))))(s)?;
+    let (s, y) = alt((
+        map(peek(located(symbol(";"))), |_| None),
+        map(cut(located(expression)), Some),
+    ))(s)?;
+    let (s, _) = context("`;` after the for-condition", cut(located(symbol(";"))))(s)?;
+    let (s, z) = alt((
+        map(peek(located(symbol(")"))), |_| None),
+        map(cut(for_step), Some),
+    ))(s)?;
+    let (s, _) = context("`)` closing the for-header", cut(located(symbol(")"))))(s)?;
+    let (s, v) = loop_body(s)?;
     Ok((
         s,
         LoopStatement::For(LoopStatementFor {
             nodes: (x, y, z, v),
+            span: Span::new(start, s),
+            keyword,
         }),
     ))
 }
 
-pub fn loop_statement_do_while(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("do")(s)?;
-    let (s, x) = statement_or_null(s)?;
-    let (s, _) = symbol("while")(s)?;
-    let (s, _) = symbol("(")(s)?;
-    let (s, y) = expression(s)?;
-    let (s, _) = symbol(")")(s)?;
-    let (s, _) = symbol(";")(s)?;
+pub fn loop_statement_do_while(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("do")(s)?;
+    let (s, x) = loop_body(s)?;
+    let (s, _) = context("`while` closing the do-while body", cut(located(symbol("while"))))(s)?;
+    let (s, _) = context("`(` opening the do-while condition", cut(located(symbol("("))))(s)?;
+    let (s, y) = context("a do-while condition expression", cut(located(expression)))(s)?;
+    let (s, _) = context("`)` closing the do-while condition", cut(located(symbol(")"))))(s)?;
+    let (s, _) = context("`;` terminating the do-while statement", cut(located(symbol(";"))))(s)?;
     Ok((
         s,
-        LoopStatement::DoWhile(LoopStatementDoWhile { nodes: (x, y) }),
+        LoopStatement::DoWhile(LoopStatementDoWhile {
+            nodes: (x, y),
+            span: Span::new(start, s),
+            keyword,
+        }),
     ))
 }
 
-pub fn loop_statement_foreach(s: &str) -> IResult<&str, LoopStatement> {
-    let (s, _) = symbol("foreach")(s)?;
-    let (s, _) = symbol("(")(s)?;
-    let (s, x) = ps_or_hierarchical_array_identifier(s)?;
-    let (s, _) = symbol("[")(s)?;
-    let (s, y) = loop_variables(s)?;
-    let (s, _) = symbol("]")(s)?;
-    let (s, _) = symbol(")")(s)?;
-    let (s, z) = statement(s)?;
+pub fn loop_statement_foreach(s: LocatedStr) -> IResult<LocatedStr, LoopStatement, ParseError> {
+    let start = s;
+    let (s, keyword) = located_symbol("foreach")(s)?;
+    let (s, _) = located(symbol("("))(s)?;
+    let (s, x) = cut(located(ps_or_hierarchical_array_identifier))(s)?;
+    let (s, _) = context("`[` opening the foreach index list", cut(located(symbol("["))))(s)?;
+    let (s, y) = context("a foreach index list", cut(loop_variables))(s)?;
+    let (s, _) = context("`]` closing the foreach index list", cut(located(symbol("]"))))(s)?;
+    let (s, _) = context("`)` closing the foreach header", cut(located(symbol(")"))))(s)?;
+    let (s, z) = foreach_body(s)?;
     Ok((
         s,
-        LoopStatement::Foreach(LoopStatementForeach { nodes: (x, y, z) }),
+        LoopStatement::Foreach(LoopStatementForeach {
+            nodes: (x, y, z),
+            span: Span::new(start, s),
+            keyword,
+        }),
     ))
 }
 
-pub fn for_initialization(s: &str) -> IResult<&str, ForInitialization> {
+pub fn for_initialization(s: LocatedStr) -> IResult<LocatedStr, ForInitialization, ParseError> {
     alt((
-        map(list_of_variable_assignments, |x| {
+        map(located(list_of_variable_assignments), |x| {
             ForInitialization::Assignment(x)
         }),
         map(
-            separated_nonempty_list(symbol(","), for_variable_declaration),
+            separated_nonempty_list(located(symbol(",")), for_variable_declaration),
             |x| ForInitialization::Declaration(x),
         ),
     ))(s)
 }
 
-pub fn for_variable_declaration(s: &str) -> IResult<&str, ForVariableDeclaration> {
-    let (s, x) = opt(symbol("var"))(s)?;
-    let (s, y) = data_type(s)?;
+pub fn for_variable_declaration(s: LocatedStr) -> IResult<LocatedStr, ForVariableDeclaration, ParseError> {
+    let start = s;
+    let (s, x) = opt(located(symbol("var")))(s)?;
+    let (s, y) = located(data_type)(s)?;
     let (s, z) = separated_nonempty_list(
-        symbol(","),
-        pair(variable_identifier, preceded(symbol("="), expression)),
+        located(symbol(",")),
+        pair(located(variable_identifier), preceded(located(symbol("=")), located(expression))),
     )(s)?;
     Ok((
         s,
         ForVariableDeclaration {
             nodes: (x.map(|_| Var {}), y, z),
+            span: Span::new(start, s),
         },
     ))
 }
 
-pub fn for_step(s: &str) -> IResult<&str, Vec<ForStepAssignment>> {
-    separated_nonempty_list(symbol(","), for_step_assignment)(s)
+pub fn for_step(s: LocatedStr) -> IResult<LocatedStr, Vec<ForStepAssignment>, ParseError> {
+    separated_nonempty_list(located(symbol(",")), for_step_assignment)(s)
 }
 
-pub fn for_step_assignment(s: &str) -> IResult<&str, ForStepAssignment> {
+pub fn for_step_assignment(s: LocatedStr) -> IResult<LocatedStr, ForStepAssignment, ParseError> {
     alt((
-        map(operator_assignment, |x| ForStepAssignment::Operator(x)),
-        map(inc_or_dec_expression, |x| ForStepAssignment::IncOrDec(x)),
-        map(function_subroutine_call, |x| {
+        map(located(operator_assignment), |x| ForStepAssignment::Operator(x)),
+        map(located(inc_or_dec_expression), |x| ForStepAssignment::IncOrDec(x)),
+        map(located(function_subroutine_call), |x| {
             ForStepAssignment::Subroutine(x)
         }),
     ))(s)
 }
 
-pub fn loop_variables(s: &str) -> IResult<&str, LoopVariables> {
-    let (s, x) = separated_nonempty_list(symbol(","), opt(index_variable_identifier))(s)?;
-    Ok((s, LoopVariables { nodes: (x,) }))
+pub fn loop_variables(s: LocatedStr) -> IResult<LocatedStr, LoopVariables, ParseError> {
+    let start = s;
+    let (s, x) = separated_nonempty_list(located(symbol(",")), opt(located(index_variable_identifier)))(s)?;
+    Ok((
+        s,
+        LoopVariables {
+            nodes: (x,),
+            span: Span::new(start, s),
+        },
+    ))
+}
+
+/// Parse one loop statement, recovering from a committed failure instead of
+/// aborting: on `Err::Failure`, push the [`Diagnostic`] and resynchronize at
+/// the next `;`, `end`, or unmatched `)`/`]`, returning a `LoopStatement::Error`
+/// placeholder in its stead.
+pub fn loop_statement_recovering<'a>(
+    diagnostics: &'a mut Vec<Diagnostic>,
+) -> impl FnMut(LocatedStr<'a>) -> IResult<LocatedStr<'a>, LoopStatement<'a>, ParseError> + 'a {
+    move |s: LocatedStr<'a>| match loop_statement(s) {
+        Ok(ok) => Ok(ok),
+        Err(nom::Err::Failure(error)) => {
+            let rest = resync(s, error.at.offset);
+            let skipped = Span::new(s, rest);
+            diagnostics.push(Diagnostic {
+                error: error.clone(),
+                skipped,
+            });
+            Ok((rest, LoopStatement::Error(LoopStatementError { skipped, error })))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse as many loop statements as possible, never bailing on the first
+/// error: this is the public entry point an editor or linter would call to
+/// get a partial AST plus every diagnostic collected along the way.
+pub fn parse_loop_statements(mut s: LocatedStr) -> (LocatedStr, Vec<LoopStatement>, Vec<Diagnostic>) {
+    let mut nodes = Vec::new();
+    let mut diagnostics = Vec::new();
+    while !s.fragment().trim_start().is_empty() {
+        match loop_statement_recovering(&mut diagnostics)(s) {
+            Ok((rest, node)) => {
+                nodes.push(node);
+                s = rest;
+            }
+            Err(nom::Err::Error(error)) => {
+                // `loop_statement_recovering` already turns a committed
+                // `Err::Failure` into an `Error` node; a bare `Err::Error`
+                // here means the next token isn't a loop statement at all.
+                // That's expected right after a recovered header, since
+                // `resync` deliberately stops AT an unmatched `)`/`]`/`end`
+                // for an enclosing parser to consume -- a role nothing
+                // plays at this top level. Record the error and step past
+                // at least one byte so it can't silently swallow the rest
+                // of the input.
+                let mut rest = resync(s, error.at.offset);
+                if rest.location_offset() <= s.location_offset() {
+                    let skip = s.fragment().len().min(1);
+                    rest = s.slice(skip..);
+                }
+                let skipped = Span::new(s, rest);
+                diagnostics.push(Diagnostic { error, skipped });
+                s = rest;
+            }
+            Err(_) => break,
+        }
+    }
+    (s, nodes, diagnostics)
+}
+
+#[cfg(feature = "serde")]
+impl<'a> LoopStatement<'a> {
+    /// Serialize this node (and the whole subtree beneath it) to JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Deserialize a `LoopStatement` tree previously produced by
+/// [`LoopStatement::to_json`]. Borrowed fields are zero-copy into `json`.
+#[cfg(feature = "serde")]
+pub fn from_json(json: &str) -> serde_json::Result<LoopStatement> {
+    serde_json::from_str(json)
 }
 
-// -----------------------------------------------------------------------------
\ No newline at end of file
+// -----------------------------------------------------------------------------
+// Constant-bound analysis
+// -----------------------------------------------------------------------------
+
+/// Whether a loop's iteration count is known at compile time, the
+/// prerequisite SystemVerilog imposes before a `for`/`repeat` loop can be
+/// synthesized or unrolled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoopBound {
+    Bounded(u64),
+    Unbounded(String),
+}
+
+/// Constant values of the `parameter`/`localparam`s visible at the loop, as
+/// resolved by elaboration; consulted by name while folding the bounds.
+pub type SymbolTable<'a> = std::collections::HashMap<&'a str, i64>;
+
+/// Fold a `repeat (count) ...` bound.
+pub fn analyze_repeat_bound(node: &LoopStatementRepeat, symbols: &SymbolTable) -> LoopBound {
+    match const_fold(&node.nodes.0, symbols) {
+        // Per the LRM a negative repeat count just means zero iterations --
+        // still fully bounded, not unbounded.
+        Some(n) if n >= 0 => LoopBound::Bounded(n as u64),
+        Some(_) => LoopBound::Bounded(0),
+        None => LoopBound::Unbounded("repeat count is not a compile-time constant".into()),
+    }
+}
+
+/// Fold a `while (cond) ...` bound. Only the degenerate constant condition
+/// (`while (0)`, zero iterations) is recognized; anything that depends on
+/// loop-carried state can't be decided by constant folding alone, so it is
+/// reported unbounded rather than guessed at.
+pub fn analyze_while_bound(node: &LoopStatementWhile, symbols: &SymbolTable) -> LoopBound {
+    match const_fold(&node.nodes.0, symbols) {
+        Some(0) => LoopBound::Bounded(0),
+        Some(_) => LoopBound::Unbounded("while condition is not a constant false".into()),
+        None => LoopBound::Unbounded("while condition is not a compile-time constant".into()),
+    }
+}
+
+/// Fold a `for (init; cond; step) ...` bound: the initialization must
+/// declare a single constant start value, the condition must compare the
+/// loop variable against a constant, and the step must be a fixed
+/// increment/decrement or a constant operator-assignment step.
+pub fn analyze_for_bound(node: &LoopStatementFor, symbols: &SymbolTable) -> LoopBound {
+    let (var, start) = match for_start(&node.nodes.0, symbols) {
+        Some(v) => v,
+        None => {
+            return LoopBound::Unbounded(
+                "for-loop initialization does not declare a single constant start".into(),
+            )
+        }
+    };
+    let limit = match &node.nodes.1 {
+        Some(cond) => match for_condition_limit(cond, &var, symbols) {
+            Some(v) => v,
+            None => {
+                return LoopBound::Unbounded(
+                    "for-loop condition does not compare the loop variable against a constant"
+                        .into(),
+                )
+            }
+        },
+        None => return LoopBound::Unbounded("for-loop has no terminating condition".into()),
+    };
+    let step = match &node.nodes.2 {
+        Some(steps) if steps.len() == 1 => match for_step_delta(&steps[0], symbols) {
+            Some(v) => v,
+            None => {
+                return LoopBound::Unbounded(
+                    "for-loop step is not a fixed increment/decrement or constant operator-assignment"
+                        .into(),
+                )
+            }
+        },
+        _ => {
+            return LoopBound::Unbounded(
+                "for-loop step is missing or steps more than one variable".into(),
+            )
+        }
+    };
+    bound_from_start_limit_step(start, limit, step)
+}
+
+/// The iteration count of a `for` loop whose start/limit/step have all
+/// already folded to constants, split out from `analyze_for_bound` so it can
+/// be exercised directly without needing a full `Expression` to construct.
+fn bound_from_start_limit_step(start: i64, limit: i64, step: i64) -> LoopBound {
+    if step == 0 {
+        return LoopBound::Unbounded("for-loop step never changes the loop variable".into());
+    }
+    if (step > 0 && limit <= start) || (step < 0 && limit >= start) {
+        return LoopBound::Bounded(0);
+    }
+    let span = (limit - start).unsigned_abs();
+    let stride = step.unsigned_abs();
+    LoopBound::Bounded((span + stride - 1) / stride)
+}
+
+/// The loop variable's name and constant start value, from a
+/// `for (int i = 0; ...; ...)`-style declaration initialization. A plain
+/// assignment initialization (`i = 0`, reusing an already-declared `i`)
+/// isn't resolved to a name this pass can track, so only the declaration
+/// form is handled here.
+fn for_start(init: &Option<ForInitialization>, symbols: &SymbolTable) -> Option<(String, i64)> {
+    match init.as_ref()? {
+        ForInitialization::Declaration(decls) if decls.len() == 1 => {
+            let (name, initial) = decls[0].nodes.2.first()?;
+            Some((name.as_str().to_string(), const_fold(initial, symbols)?))
+        }
+        _ => None,
+    }
+}
+
+/// Fold `cond` as "the loop variable `var` compared against a constant",
+/// returning that constant. Only a relational comparison where exactly one
+/// side is a bare reference to `var` is recognized -- folding the condition
+/// as a whole would fold away the loop variable itself (which is never in
+/// `symbols`) and fail on every ordinary `i < 10`-style condition.
+fn for_condition_limit(cond: &Expression, var: &str, symbols: &SymbolTable) -> Option<i64> {
+    match cond {
+        Expression::BinaryExpression(x) => {
+            let op = x.nodes.1;
+            if !matches!(
+                op,
+                BinaryOperator::Lt | BinaryOperator::Le | BinaryOperator::Gt | BinaryOperator::Ge
+            ) {
+                return None;
+            }
+            let lhs_is_var = is_identifier(&x.nodes.0, var);
+            let rhs_is_var = is_identifier(&x.nodes.2, var);
+            // Normalize to "the loop variable on the left": when var is on
+            // the right (e.g. `10 > i`, which means `i < 10`), the operator
+            // as seen from the variable's side is mirrored.
+            let (limit, op) = if lhs_is_var && !rhs_is_var {
+                (const_fold(&x.nodes.2, symbols)?, op)
+            } else if rhs_is_var && !lhs_is_var {
+                let mirrored = match op {
+                    BinaryOperator::Lt => BinaryOperator::Gt,
+                    BinaryOperator::Le => BinaryOperator::Ge,
+                    BinaryOperator::Gt => BinaryOperator::Lt,
+                    BinaryOperator::Ge => BinaryOperator::Le,
+                    _ => unreachable!(),
+                };
+                (const_fold(&x.nodes.0, symbols)?, mirrored)
+            } else {
+                return None;
+            };
+            // `bound_from_start_limit_step` only understands a strict,
+            // exclusive limit (as `Lt`/`Gt` already give it); normalize the
+            // inclusive `Le`/`Ge` forms to the equivalent exclusive value
+            // rather than undercounting the last iteration. Checked, like
+            // every other fold in this function -- an i64::MAX/MIN limit
+            // should fall back to "not a compile-time constant" instead of
+            // wrapping.
+            match op {
+                BinaryOperator::Le => limit.checked_add(1),
+                BinaryOperator::Ge => limit.checked_sub(1),
+                _ => Some(limit),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_identifier(expr: &Expression, name: &str) -> bool {
+    matches!(
+        expr,
+        Expression::Primary(Primary::HierarchicalIdentifier(id)) if id.identifier.as_str() == name
+    )
+}
+
+fn for_step_delta(step: &ForStepAssignment, symbols: &SymbolTable) -> Option<i64> {
+    match step {
+        ForStepAssignment::IncOrDec(x) => match x.nodes.1 {
+            IncOrDecOperator::Increment => Some(1),
+            IncOrDecOperator::Decrement => Some(-1),
+        },
+        ForStepAssignment::Operator(x) => match x.nodes.1 {
+            AssignmentOperator::Add => const_fold(&x.nodes.2, symbols),
+            AssignmentOperator::Sub => const_fold(&x.nodes.2, symbols).map(|v| -v),
+            _ => None,
+        },
+        ForStepAssignment::Subroutine(_) => None,
+    }
+}
+
+/// Constant-fold `expr` against `symbols`, handling integer literals, named
+/// `parameter`/`localparam` references, and the usual arithmetic operators.
+/// Anything else (signals, function calls) folds to `None`, which callers
+/// treat as an unbounded loop.
+fn const_fold(expr: &Expression, symbols: &SymbolTable) -> Option<i64> {
+    match expr {
+        Expression::Primary(primary) => const_fold_primary(primary, symbols),
+        Expression::UnaryExpression(x) => {
+            let value = const_fold(&x.nodes.1, symbols)?;
+            match x.nodes.0 {
+                UnaryOperator::Plus => Some(value),
+                UnaryOperator::Minus => Some(-value),
+                _ => None,
+            }
+        }
+        Expression::BinaryExpression(x) => {
+            let lhs = const_fold(&x.nodes.0, symbols)?;
+            let rhs = const_fold(&x.nodes.2, symbols)?;
+            match x.nodes.1 {
+                BinaryOperator::Add => lhs.checked_add(rhs),
+                BinaryOperator::Sub => lhs.checked_sub(rhs),
+                BinaryOperator::Mul => lhs.checked_mul(rhs),
+                BinaryOperator::Div if rhs != 0 => lhs.checked_div(rhs),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn const_fold_primary(primary: &Primary, symbols: &SymbolTable) -> Option<i64> {
+    match primary {
+        Primary::Number(n) => n.as_i64(),
+        Primary::HierarchicalIdentifier(id) => symbols.get(id.identifier.as_str()).copied(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod bound_tests {
+    use super::*;
+
+    #[test]
+    fn counts_up_exactly() {
+        // for (i = 0; i < 10; i++) -> 10 iterations
+        assert_eq!(bound_from_start_limit_step(0, 10, 1), LoopBound::Bounded(10));
+    }
+
+    #[test]
+    fn counts_up_with_remainder() {
+        // for (i = 0; i < 10; i += 3) -> 0, 3, 6, 9 -> 4 iterations
+        assert_eq!(bound_from_start_limit_step(0, 10, 3), LoopBound::Bounded(4));
+    }
+
+    #[test]
+    fn counts_down() {
+        // for (i = 10; i > 0; i--) -> 10 iterations
+        assert_eq!(bound_from_start_limit_step(10, 0, -1), LoopBound::Bounded(10));
+    }
+
+    #[test]
+    fn zero_iterations_when_condition_already_false() {
+        assert_eq!(bound_from_start_limit_step(10, 0, 1), LoopBound::Bounded(0));
+    }
+
+    #[test]
+    fn zero_step_is_unbounded() {
+        assert!(matches!(
+            bound_from_start_limit_step(0, 10, 0),
+            LoopBound::Unbounded(_)
+        ));
+    }
+
+    // `for_condition_limit` normalizes an inclusive `<=`/`>=` condition to
+    // the equivalent exclusive limit before handing it to
+    // `bound_from_start_limit_step` (limit+1 for `<=`, limit-1 for `>=`);
+    // these exercise that normalized arithmetic directly.
+
+    #[test]
+    fn counts_up_inclusive_le() {
+        // for (i = 0; i <= 10; i++) -> 11 iterations (i = 0..=10), not 10
+        assert_eq!(bound_from_start_limit_step(0, 10 + 1, 1), LoopBound::Bounded(11));
+    }
+
+    #[test]
+    fn counts_down_inclusive_ge() {
+        // for (i = 10; i >= 0; i--) -> 11 iterations (i = 10..=0), not 10
+        assert_eq!(bound_from_start_limit_step(10, 0 - 1, -1), LoopBound::Bounded(11));
+    }
+}
+
+#[cfg(test)]
+mod recovery_tests {
+    use super::*;
+
+    #[test]
+    fn resync_stops_before_unmatched_close_paren() {
+        let s = LocatedStr::new("i < 10) foreach (a) ;");
+        let rest = resync(s, s.location_offset());
+        assert_eq!(*rest.fragment(), ") foreach (a) ;");
+    }
+
+    #[test]
+    fn resync_skips_balanced_parens_before_semicolon() {
+        let s = LocatedStr::new("foo(1, 2); bar();");
+        let rest = resync(s, s.location_offset());
+        assert_eq!(*rest.fragment(), " bar();");
+    }
+
+    #[test]
+    fn resync_stops_at_end_keyword_not_identifier_prefix() {
+        let s = LocatedStr::new("endfoo end bar");
+        let rest = resync(s, s.location_offset());
+        assert_eq!(*rest.fragment(), "end bar");
+    }
+
+    #[test]
+    fn resync_consumes_rest_of_input_when_no_sync_token() {
+        let s = LocatedStr::new("i < 10");
+        let rest = resync(s, s.location_offset());
+        assert_eq!(*rest.fragment(), "");
+    }
+
+    #[test]
+    fn report_renders_caret_at_column() {
+        let error = ParseError {
+            expected: "`;` after for-initialization".to_string(),
+            at: Span {
+                offset: 17,
+                len: 0,
+                line: 3,
+                col: 18,
+            },
+        };
+        let rendered = error.report("for (i = 0\nj < 10\n    for (i = 0 j < 10; i++)");
+        assert!(rendered.contains("expected `;` after for-initialization"));
+        assert!(rendered.contains("line 3, column 18"));
+        assert!(rendered.ends_with("^"));
+    }
+}
+
+#[cfg(test)]
+mod parse_tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loop_statement_error_round_trips_through_json() {
+        let original = LoopStatement::Error(LoopStatementError {
+            skipped: Span {
+                offset: 3,
+                len: 5,
+                line: 1,
+                col: 4,
+            },
+            error: ParseError {
+                expected: "`;` after for-initialization".to_string(),
+                at: Span {
+                    offset: 3,
+                    len: 0,
+                    line: 1,
+                    col: 4,
+                },
+            },
+        });
+        let json = original.to_json().unwrap();
+        let round_tripped = from_json(&json).unwrap();
+        match (original, round_tripped) {
+            (LoopStatement::Error(a), LoopStatement::Error(b)) => {
+                assert_eq!(a.skipped, b.skipped);
+                assert_eq!(a.error, b.error);
+            }
+            other => panic!("expected LoopStatement::Error on both sides, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keyword_span_points_at_the_token_not_leading_whitespace() {
+        let s = LocatedStr::new("  for (i=0;i<1;i++) ;");
+        let (_, keyword) = located_symbol("for")(s).unwrap();
+        assert_eq!(keyword.offset, 2);
+        assert_eq!(keyword.col, 3);
+    }
+
+    #[test]
+    fn malformed_loop_body_recovers_locally_instead_of_failing_the_loop() {
+        // The while-condition itself is fine; only the body statement is
+        // malformed. That should produce a `LoopBody::Error` placeholder
+        // while still capturing the loop's own header and span, rather than
+        // discarding the whole `LoopStatement::While` the way a malformed
+        // header would.
+        let s = LocatedStr::new("while (1) +;");
+        let (_, node) = loop_statement_while(s).expect("a malformed body recovers, not fails");
+        let body = match node {
+            LoopStatement::While(x) => x.nodes.1,
+            other => panic!("expected LoopStatement::While, got {:?}", other),
+        };
+        assert!(matches!(body, LoopBody::Error(_)));
+    }
+
+    #[test]
+    fn malformed_while_condition_is_a_hard_failure() {
+        let s = LocatedStr::new("while (1 +) x = 1;");
+        match loop_statement_while(s) {
+            Err(nom::Err::Failure(_)) => {}
+            other => panic!("expected Err::Failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_for_step_is_a_hard_failure() {
+        let s = LocatedStr::new("for (i = 0; i < 10; +) x = 1;");
+        match loop_statement_for(s) {
+            Err(nom::Err::Failure(_)) => {}
+            other => panic!("expected Err::Failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_foreach_index_list_is_a_hard_failure() {
+        let s = LocatedStr::new("foreach (a[+]) x = 1;");
+        match loop_statement_foreach(s) {
+            Err(nom::Err::Failure(_)) => {}
+            other => panic!("expected Err::Failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_loop_keyword_backtracks_instead_of_failing() {
+        // No loop keyword matched at all -- this must stay a plain
+        // `Err::Error` so `alt` in `loop_statement` can still try the other
+        // forms (and so `loop_statement_recovering` doesn't treat ordinary
+        // non-loop input as a committed parse failure).
+        match loop_statement(LocatedStr::new("x = 1;")) {
+            Err(nom::Err::Error(_)) => {}
+            other => panic!("expected Err::Error, got {:?}", other),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------